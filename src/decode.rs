@@ -0,0 +1,101 @@
+//! Frame decoding shared by the capture binaries: turn raw UVC frames (YUYV or
+//! MJPEG) into full-color `RgbImage`s, and derive per-pixel luma.
+
+use anyhow::Result;
+use image::{Rgb, RgbImage};
+
+/// Convert a single YUV triple to an 8-bit RGB pixel, clamping each channel.
+pub fn yuv_to_rgb(y: f32, u: f32, v: f32) -> Rgb<u8> {
+    let r = y + 1.402 * (v - 128.0);
+    let g = y - 0.344 * (u - 128.0) - 0.714 * (v - 128.0);
+    let b = y + 1.772 * (u - 128.0);
+    Rgb([
+        r.clamp(0.0, 255.0) as u8,
+        g.clamp(0.0, 255.0) as u8,
+        b.clamp(0.0, 255.0) as u8,
+    ])
+}
+
+/// Expand a packed `Y0 U Y1 V` YUYV buffer into a full-color `RgbImage`.
+///
+/// UVC can deliver short/partial frames on transfer errors; a buffer smaller
+/// than `width*height*2` (rows rounded up to whole 4-byte macropixels) is
+/// rejected rather than indexed out of bounds.
+pub fn decode_yuyv(bytes: &[u8], width: u32, height: u32) -> Result<RgbImage> {
+    let expected = (width as usize + 1) / 2 * 4 * height as usize;
+    if bytes.len() < expected {
+        return Err(anyhow::anyhow!("short YUYV frame: got {} bytes, expected {}", bytes.len(), expected));
+    }
+    let mut image = RgbImage::new(width, height);
+    let mut offset = 0;
+    for y in 0..height {
+        let mut x = 0;
+        while x < width {
+            let y0 = bytes[offset] as f32;
+            let u = bytes[offset + 1] as f32;
+            let y1 = bytes[offset + 2] as f32;
+            let v = bytes[offset + 3] as f32;
+            image.put_pixel(x, y, yuv_to_rgb(y0, u, v));
+            if x + 1 < width {
+                image.put_pixel(x + 1, y, yuv_to_rgb(y1, u, v));
+            }
+            offset += 4;
+            x += 2;
+        }
+    }
+    Ok(image)
+}
+
+/// Decode an MJPEG frame into an `RgbImage` via the baseline JPEG decoder.
+pub fn decode_mjpeg(bytes: &[u8]) -> Result<RgbImage> {
+    let mut decoder = jpeg_decoder::Decoder::new(bytes);
+    let pixels = decoder.decode()?;
+    let info = decoder.info().ok_or_else(|| anyhow::anyhow!("missing jpeg metadata"))?;
+    RgbImage::from_raw(info.width as u32, info.height as u32, pixels)
+        .ok_or_else(|| anyhow::anyhow!("decoded jpeg buffer does not match its dimensions"))
+}
+
+/// Decode a captured frame into an `RgbImage`, regardless of the wire format.
+pub fn decode_frame(format: uvc::FrameFormat, bytes: &[u8], width: u32, height: u32) -> Result<RgbImage> {
+    match format {
+        uvc::FrameFormat::YUYV => decode_yuyv(bytes, width, height),
+        uvc::FrameFormat::MJPEG => decode_mjpeg(bytes),
+        other => Err(anyhow::anyhow!("unsupported frame format {:?}", other)),
+    }
+}
+
+/// Rec. 601 luma of an RGB pixel.
+pub fn luma(pixel: &Rgb<u8>) -> u8 {
+    let Rgb([r, g, b]) = *pixel;
+    (0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yuv_to_rgb_matches_known_conversion() {
+        // Mid-gray luma with u/v at neutral (128) stays gray.
+        assert_eq!(yuv_to_rgb(128.0, 128.0, 128.0), Rgb([128, 128, 128]));
+        // Full-scale luma with a saturated V channel pushes red to the clamp
+        // and pulls green down, leaving blue untouched.
+        assert_eq!(yuv_to_rgb(255.0, 128.0, 255.0), Rgb([255, 164, 255]));
+    }
+
+    #[test]
+    fn decode_yuyv_rejects_short_frames() {
+        let bytes = vec![0u8; 4]; // half of the 8 bytes a 2x2 frame needs.
+        let err = decode_yuyv(&bytes, 2, 2).unwrap_err();
+        assert!(err.to_string().contains("short YUYV frame"));
+    }
+
+    #[test]
+    fn decode_yuyv_decodes_a_single_macropixel() {
+        // One YUYV macropixel covering two horizontally adjacent gray pixels.
+        let bytes = [128u8, 128, 128, 128];
+        let image = decode_yuyv(&bytes, 2, 1).unwrap();
+        assert_eq!(*image.get_pixel(0, 0), Rgb([128, 128, 128]));
+        assert_eq!(*image.get_pixel(1, 0), Rgb([128, 128, 128]));
+    }
+}
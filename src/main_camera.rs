@@ -1,17 +1,231 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 use anyhow::Result;
 use clap::{Arg, App as ClapApp, SubCommand, AppSettings};
 use std::io::prelude::*;
 use once_cell::sync::OnceCell;
 use sysfs_gpio::{Direction, Pin};
 use std::thread::sleep;
+use image::RgbImage;
+use serde::{Deserialize, Serialize};
+
+mod decode;
+use decode::{decode_frame, luma};
 
 const CMD_DEVICES : &str = "devices";
 const CMD_RUN : &str = "run";
 const CMD_LEDSTEST : &str = "ledstest";
+const CMD_CALIBRATE : &str = "calibrate";
+const CONFIG_PATH : &str = "maimai.json";
+const PIN_COUNT : usize = 8;
+const DEFAULT_DURATION : u64 = 180;
 const ARG_DEVICEID : &str = "deviceid";
+const ARG_VID : &str = "vid";
+const ARG_PID : &str = "pid";
+const ARG_SERIAL : &str = "serial";
+const ARG_PRODUCT : &str = "product";
+const ARG_EXPOSURE : &str = "exposure";
+const ARG_GAIN : &str = "gain";
+const ARG_FOCUS_ABS : &str = "focus-abs";
+const ARG_FOCUS_REL : &str = "focus-rel";
+const ARG_WHITE_BALANCE : &str = "white-balance";
+const ARG_SCANNING_MODE : &str = "scanning-mode";
+const ARG_AE_PRIORITY : &str = "ae-priority";
+const ARG_DUMP_CONTROLS : &str = "dump-controls";
+const ARG_MAPPING : &str = "mapping";
+const ARG_THRESHOLD : &str = "threshold";
+const ARG_AUTO_THRESHOLD : &str = "auto-threshold";
+const ARG_WIDTH : &str = "width";
+const ARG_HEIGHT : &str = "height";
+const ARG_FPS : &str = "fps";
+const ARG_FORMAT : &str = "format";
+const ARG_DURATION : &str = "duration";
+
+/// Parse a pixel-format name into the matching `uvc::FrameFormat`.
+fn parse_frame_format(value: &str) -> Result<uvc::FrameFormat> {
+    match value.to_ascii_lowercase().as_str() {
+        "yuyv" => Ok(uvc::FrameFormat::YUYV),
+        "mjpeg" => Ok(uvc::FrameFormat::MJPEG),
+        other => Err(anyhow::anyhow!("unknown format {:?} (expected yuyv or mjpeg)", other)),
+    }
+}
+
+/// Absolute camera control values to apply before streaming. Any field left as
+/// `None` keeps the value already programmed into the device.
+#[derive(Default)]
+struct CameraControls {
+    exposure_abs: Option<u32>,
+    gain: Option<u16>,
+    focus_abs: Option<u16>,
+    focus_rel: Option<i8>,
+    white_balance: Option<u16>,
+    scanning_mode: Option<u8>,
+    ae_priority: Option<u8>,
+}
+
+impl CameraControls {
+    /// Program every control that was requested onto the opened device.
+    fn apply(&self, devh: &uvc::DeviceHandle) -> Result<()> {
+        if let Some(exposure) = self.exposure_abs {
+            devh.set_exposure_abs(exposure)?;
+        }
+        if let Some(gain) = self.gain {
+            devh.set_gain(gain)?;
+        }
+        if let Some(focus) = self.focus_abs {
+            devh.set_focus_abs(focus)?;
+        }
+        if let Some(focus) = self.focus_rel {
+            devh.set_focus_rel(focus)?;
+        }
+        if let Some(white_balance) = self.white_balance {
+            devh.set_white_balance_temperature(white_balance)?;
+        }
+        if let Some(mode) = self.scanning_mode {
+            devh.set_scanning_mode(mode)?;
+        }
+        if let Some(priority) = self.ae_priority {
+            devh.set_ae_priority(priority)?;
+        }
+        Ok(())
+    }
+
+    /// Print the current value of every control, tolerating unsupported ones.
+    fn dump(devh: &uvc::DeviceHandle) {
+        fn show<T: std::fmt::Debug>(name: &str, value: uvc::Result<T>) {
+            match value {
+                Ok(value) => println!("{:<16} {:?}", name, value),
+                Err(err) => println!("{:<16} unsupported ({})", name, err),
+            }
+        }
+        show("exposure_abs", devh.exposure_abs());
+        show("gain", devh.gain());
+        show("focus_abs", devh.focus_abs());
+        show("white_balance", devh.white_balance_temperature());
+        show("scanning_mode", devh.scanning_mode());
+        show("ae_priority", devh.ae_priority());
+    }
+}
+
+/// Criteria used to locate a UVC device. Any field left as `None` is ignored;
+/// a device matches when it satisfies every field that is set.
+#[derive(Default)]
+struct DeviceQuery {
+    bus_address: Option<String>,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial: Option<String>,
+    product: Option<String>,
+}
+
+impl DeviceQuery {
+    /// Whether `device` satisfies every criterion set on this query.
+    fn matches(&self, device: &uvc::Device) -> bool {
+        if let Some(bus_address) = &self.bus_address {
+            let parts: Vec<_> = bus_address.split(':').collect();
+            match (parts.first().and_then(|s| s.parse::<u8>().ok()),
+                   parts.get(1).and_then(|s| s.parse::<u8>().ok())) {
+                (Some(bus), Some(address)) => {
+                    if bus != device.bus_number() || address != device.device_address() {
+                        return false;
+                    }
+                }
+                _ => return false,
+            }
+        }
+        let description = match device.description() {
+            Ok(description) => description,
+            Err(_) => return false,
+        };
+        if let Some(vid) = self.vid {
+            if vid != description.vendor_id {
+                return false;
+            }
+        }
+        if let Some(pid) = self.pid {
+            if pid != description.product_id {
+                return false;
+            }
+        }
+        if let Some(serial) = &self.serial {
+            if description.serial_number.as_deref() != Some(serial.as_str()) {
+                return false;
+            }
+        }
+        if let Some(product) = &self.product {
+            match &description.product {
+                Some(name) if name.contains(product) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Parse a hexadecimal USB id such as `046d` or `0x046d`.
+fn parse_usb_id(value: &str) -> Result<u16> {
+    let trimmed = value.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(trimmed, 16).map_err(|err| anyhow::anyhow!("invalid USB id {:?}: {}", value, err))
+}
+
+/// Attach the shared device-selection arguments to a subcommand.
+fn device_args<'a, 'b>(cmd: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    cmd
+        .arg(Arg::with_name(ARG_DEVICEID).takes_value(true).index(1)
+            .help("Select the device by its bus:address"))
+        .arg(Arg::with_name(ARG_VID).long(ARG_VID).takes_value(true)
+            .help("Select the device by its USB vendor id (hex)"))
+        .arg(Arg::with_name(ARG_PID).long(ARG_PID).takes_value(true)
+            .help("Select the device by its USB product id (hex)"))
+        .arg(Arg::with_name(ARG_SERIAL).long(ARG_SERIAL).takes_value(true)
+            .help("Select the device by its serial number"))
+        .arg(Arg::with_name(ARG_PRODUCT).long(ARG_PRODUCT).takes_value(true)
+            .help("Select the device by a product-name substring"))
+}
+
+/// Build a `DeviceQuery` from the device-selection arguments.
+fn device_query(matches: &clap::ArgMatches) -> Result<DeviceQuery> {
+    Ok(DeviceQuery {
+        bus_address: matches.value_of(ARG_DEVICEID).map(|s| s.to_string()),
+        vid: matches.value_of(ARG_VID).map(parse_usb_id).transpose()?,
+        pid: matches.value_of(ARG_PID).map(parse_usb_id).transpose()?,
+        serial: matches.value_of(ARG_SERIAL).map(|s| s.to_string()),
+        product: matches.value_of(ARG_PRODUCT).map(|s| s.to_string()),
+    })
+}
+
+/// Whether `decode_frame` can turn this wire format into an `RgbImage`.
+fn is_decodable(format: uvc::FrameFormat) -> bool {
+    matches!(format, uvc::FrameFormat::YUYV | uvc::FrameFormat::MJPEG)
+}
+
+/// Use `requested` when the device can stream it. Otherwise pick the
+/// largest-area, then highest-fps format the device actually offers,
+/// restricted to formats we can decode so we never negotiate into a mode that
+/// makes every frame fail. Falls back to `requested` when the device exposes no
+/// decodable format.
+fn negotiate_format(devh: &uvc::DeviceHandle, requested: uvc::StreamFormat) -> uvc::StreamFormat {
+    if is_decodable(requested.format) && devh.get_stream_handle_with_format(requested).is_ok() {
+        return requested;
+    }
+    let preferred = devh.get_preferred_format(|a, b| {
+        // Always favour a decodable format over an undecodable one.
+        match (is_decodable(a.format), is_decodable(b.format)) {
+            (true, false) => a,
+            (false, true) => b,
+            _ => {
+                let area_a = a.width as u64 * a.height as u64;
+                let area_b = b.width as u64 * b.height as u64;
+                if (area_a, a.fps) >= (area_b, b.fps) { a } else { b }
+            }
+        }
+    });
+    match preferred {
+        Some(format) if is_decodable(format.format) => format,
+        _ => requested,
+    }
+}
 
 struct GPIO{
     pins: Vec<Pin>
@@ -19,7 +233,7 @@ struct GPIO{
 impl GPIO {
     pub fn new() -> Self {
         let mut pins = Vec::new();
-        for pin_no in 11..=18 {
+        for pin_no in 11..(11 + PIN_COUNT as u64) {
             pins.push(Pin::new(pin_no));
         }
         GPIO { pins }
@@ -58,9 +272,131 @@ impl GPIO {
         }
         Ok(())
     }
+
+    /// Drive each pin independently from a `0.0..=1.0` level already scaled
+    /// against the calibrated `pindiv` (see [`region_levels`]), so the strip
+    /// shows a spatial map of the scene instead of a single bar graph. Levels
+    /// beyond the number of pins are ignored; missing levels stay off.
+    pub fn signal_regions(&self, levels: &[f32]) -> Result<()> {
+        for (n, pin) in self.pins.iter().enumerate() {
+            let level = levels.get(n).copied().unwrap_or(0.0);
+            // Pins are active-low: a `0` lights the LED.
+            let value = if level >= 0.5 { 0 } else { 1 };
+            pin.set_value(value)?;
+            println!("P {} {:.2} {}", n, level, value);
+        }
+        Ok(())
+    }
+}
+
+/// How per-frame brightness is mapped onto the LED strip.
+#[derive(Clone, Copy)]
+enum Mapping {
+    /// A single aggregate level shown as a bar graph across all pins.
+    Bar,
+    /// One level per pin, computed from a vertical slice of the frame.
+    Regions,
+}
+
+impl std::str::FromStr for Mapping {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bar" => Ok(Mapping::Bar),
+            "regions" => Ok(Mapping::Regions),
+            other => Err(anyhow::anyhow!("unknown mapping {:?} (expected bar or regions)", other)),
+        }
+    }
+}
+
+/// Over-threshold pixel count of each of `columns` equal vertical slices,
+/// normalized against `pindiv` (the same calibrated "pixels per pin" scale
+/// `cmd_calibrate` derives for [`GPIO::signal`]) rather than the column's raw
+/// pixel area. The raw over-threshold fraction of a column is tiny at a
+/// near-white threshold, so scaling by its area would keep every level far
+/// below the `0.5` cutoff `signal_regions` applies; `pindiv` is calibrated
+/// against real captured brightness instead.
+fn region_levels(image: &RgbImage, columns: usize, treshold: u8, pindiv: usize) -> Vec<f32> {
+    let width = image.width();
+    let mut counts = vec![0u32; columns];
+    for (x, _y, pixel) in image.enumerate_pixels() {
+        if luma(pixel) > treshold {
+            let column = (x as usize * columns / width.max(1) as usize).min(columns - 1);
+            counts[column] += 1;
+        }
+    }
+    let pindiv = pindiv.max(1) as f32;
+    counts.iter().map(|&c| (c as f32 / pindiv).min(1.0)).collect()
 }
 
 
+/// A decoded frame tagged with the instant it was captured, so the consumer can
+/// measure the end-to-end latency of the pipeline.
+struct CapturedFrame {
+    image: RgbImage,
+    captured: Instant,
+}
+
+/// Threshold and LED scaling chosen by calibration, persisted between runs.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct Calibration {
+    treshold: u8,
+    pindiv: usize,
+}
+
+impl Calibration {
+    /// Load the calibration from `CONFIG_PATH`, if it exists and parses.
+    fn load() -> Option<Self> {
+        std::fs::read_to_string(CONFIG_PATH).ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+    }
+
+    /// Persist the calibration to `CONFIG_PATH`.
+    fn save(&self) -> Result<()> {
+        std::fs::write(CONFIG_PATH, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Pick the threshold that maximizes between-class variance (Otsu's method)
+/// over a 256-bin luma histogram.
+fn otsu_threshold(histogram: &[u64; 256]) -> u8 {
+    let total: u64 = histogram.iter().sum();
+    if total == 0 {
+        return 230;
+    }
+    let sum_all: f64 = histogram.iter().enumerate().map(|(t, &c)| t as f64 * c as f64).sum();
+    let total = total as f64;
+    let mut w0 = 0.0;
+    let mut sum0 = 0.0;
+    let mut best_t = 0u8;
+    let mut best_var = -1.0;
+    for t in 0..256 {
+        w0 += histogram[t] as f64;
+        if w0 == 0.0 {
+            continue;
+        }
+        let w1 = total - w0;
+        if w1 == 0.0 {
+            break;
+        }
+        sum0 += t as f64 * histogram[t] as f64;
+        let mu0 = sum0 / w0;
+        let mu1 = (sum_all - sum0) / w1;
+        let var = w0 * w1 * (mu0 - mu1) * (mu0 - mu1);
+        if var > best_var {
+            best_var = var;
+            best_t = t as u8;
+        }
+    }
+    best_t
+}
+
+/// Number of pixels in a luma histogram that are brighter than `treshold`.
+fn over_treshold(histogram: &[u64; 256], treshold: u8) -> u64 {
+    histogram[(treshold as usize + 1).min(256)..].iter().sum()
+}
+
 fn cmd_device_list() -> Result<()> {
     let ctx = uvc::Context::new().expect("Could not get context");
 
@@ -69,7 +405,8 @@ fn cmd_device_list() -> Result<()> {
     let mut count = 0;
     for device in devices {
         let description = device.description()?;
-        println!("- {}:{} {:?} {:?}",device.bus_number(),device.device_address(),
+        println!("- {}:{} {:04x}:{:04x} {:?} {:?}",device.bus_number(),device.device_address(),
+            description.vendor_id,description.product_id,
             description.product,description.serial_number);
         count += 1;
     }
@@ -78,8 +415,81 @@ fn cmd_device_list() -> Result<()> {
     Ok(())
 }
 
-fn cmd_run(device_id : String) {
-    capture_video(device_id).expect("failed to capture video");
+fn cmd_run(query : DeviceQuery, requested : uvc::StreamFormat, controls : CameraControls, dump : bool,
+           mapping : Mapping, treshold : Option<u8>, auto_treshold : bool, duration : u64) {
+    // Fall back to the persisted calibration, then to the historical defaults.
+    let calibration = Calibration::load();
+    let treshold = treshold
+        .or_else(|| calibration.map(|c| c.treshold))
+        .unwrap_or(230);
+    let pindiv = calibration.map(|c| c.pindiv).unwrap_or(100);
+    capture_video(query, requested, controls, dump, mapping, treshold, pindiv, auto_treshold, duration)
+        .expect("failed to capture video");
+}
+
+fn cmd_calibrate(query : DeviceQuery) -> Result<()> {
+    let ctx = uvc::Context::new()?;
+    let dev = ctx.devices()?.find(|d| query.matches(d));
+    let dev = match dev {
+        Some(dev) => dev,
+        None => {
+            println!("Device not found");
+            return Ok(());
+        }
+    };
+    let devh = dev.open()?;
+    devh.set_ae_mode(uvc::AutoExposureMode::Manual).ok();
+
+    let requested = uvc::StreamFormat {
+        width: 640,
+        height: 480,
+        fps: 10,
+        format: uvc::FrameFormat::YUYV,
+    };
+    let format = negotiate_format(&devh, requested);
+    let pixel_format = format.format;
+    let mut streamh = devh.get_stream_handle_with_format(format)?;
+
+    // Collect one luma histogram per captured frame for a few seconds.
+    let histograms = Arc::new(std::sync::Mutex::new(Vec::<[u64; 256]>::new()));
+    let stream = streamh.start_stream(
+        move |frame, collector: &mut Arc<std::sync::Mutex<Vec<[u64; 256]>>>| {
+            if let Ok(image) = decode_frame(pixel_format, frame.to_bytes(), frame.width(), frame.height()) {
+                let mut histogram = [0u64; 256];
+                for pixel in image.pixels() {
+                    histogram[luma(pixel) as usize] += 1;
+                }
+                if let Ok(mut all) = collector.lock() {
+                    all.push(histogram);
+                }
+            }
+        },
+        histograms.clone(),
+    )?;
+    std::thread::sleep(Duration::new(3, 0));
+    stream.stop();
+
+    let histograms = histograms.lock().unwrap();
+    if histograms.is_empty() {
+        return Err(anyhow::anyhow!("captured no frames during calibration"));
+    }
+
+    let mut aggregate = [0u64; 256];
+    for histogram in histograms.iter() {
+        for (bin, count) in histogram.iter().enumerate() {
+            aggregate[bin] += count;
+        }
+    }
+    let treshold = otsu_threshold(&aggregate);
+
+    // Scale the LED bar so the brightest observed frame fills every pin.
+    let max_count = histograms.iter().map(|h| over_treshold(h, treshold)).max().unwrap_or(0);
+    let pindiv = (max_count as usize / PIN_COUNT.max(1)).max(1);
+
+    let calibration = Calibration { treshold, pindiv };
+    calibration.save()?;
+    println!("Calibrated treshold={} pindiv={} (saved to {})", treshold, pindiv, CONFIG_PATH);
+    Ok(())
 }
 
 fn cmd_leds_test() {
@@ -88,19 +498,17 @@ fn cmd_leds_test() {
     gpio.test().expect("Cannot test GPIOs");
 }
 
-fn capture_video(device_id : String) -> Result<()> {
+fn capture_video(query : DeviceQuery, requested : uvc::StreamFormat, controls : CameraControls, dump : bool, mapping : Mapping, treshold : u8, pindiv : usize, auto_treshold : bool, duration : u64) -> Result<()> {
     // Get a libuvc context
     let ctx = uvc::Context::new()?;
-    let bus_address :Vec<_> = device_id.split(":").collect();
-    let bus = u8::from_str_radix(bus_address[0],10)?;
-    let address = u8::from_str_radix(bus_address[1],10)?;
 
-    let dev = ctx.devices()?.find(|d| bus==d.bus_number() && address==d.device_address());
+    let dev = ctx.devices()?.find(|d| query.matches(d));
     if dev.is_none() {
         println!("Device not found");
         return Ok(());
     }
     let dev = dev.unwrap();
+    let (bus, address) = (dev.bus_number(), dev.device_address());
 
     // The device must be opened to create a handle to the device
     let devh = {
@@ -114,15 +522,17 @@ fn capture_video(device_id : String) -> Result<()> {
         }
     };
 
-    // Most webcams support this format
-    let format = uvc::StreamFormat {
-        width: 640,
-        height: 480,
-        fps: 10,
-        format: uvc::FrameFormat::YUYV,
-    };
-
     devh.set_ae_mode(uvc::AutoExposureMode::Manual).expect("cannot disable auto exposure");
+    controls.apply(&devh)?;
+    if dump {
+        CameraControls::dump(&devh);
+        return Ok(());
+    }
+    // Negotiate the actual format the camera supports, preferring the requested
+    // mode, then the biggest/fastest one.
+    let format = negotiate_format(&devh, requested);
+    println!("Streaming {}x{} @ {}fps {:?}", format.width, format.height, format.fps, format.format);
+    let pixel_format = format.format;
     // Get the necessary stream information
     let mut streamh = devh
         .get_stream_handle_with_format(format)
@@ -133,46 +543,131 @@ fn capture_video(device_id : String) -> Result<()> {
     // the callback used in the stream
     let counter = Arc::new(AtomicUsize::new(0));
 
-    let gpio = GPIO::new();
-    gpio.init()?;
+    // Decouple the UVC callback from the per-pixel analysis and GPIO writes: the
+    // callback only hands the newest frame to a bounded channel (dropping stale
+    // frames on overrun), while a dedicated worker does the slow work. That keeps
+    // a stalled GPIO write or `println!` from backing up the capture thread.
+    // A single-slot mailbox the capture callback overwrites with the newest
+    // frame. There is exactly one consumer, so the callback never races the
+    // worker for frames; overwriting a still-full slot is the drop signal.
+    let slot = Arc::new((Mutex::new(Option::<CapturedFrame>::None), Condvar::new()));
+    let running = Arc::new(AtomicBool::new(true));
+    let dropped = Arc::new(AtomicUsize::new(0));
+
+    let worker_slot = slot.clone();
+    let worker_running = running.clone();
+    let worker_dropped = dropped.clone();
+    let worker = std::thread::spawn(move || {
+        let gpio = GPIO::new();
+        gpio.init().expect("Cannot init GPIOs");
+        let columns = gpio.pins.len();
+
+        let mut treshold = treshold;
+        let mut accum = [0u64; 256];
+        let mut processed = 0u64;
+        loop {
+            // Wait for a frame to be deposited, or for shutdown.
+            let frame = {
+                let (lock, cvar) = &*worker_slot;
+                let mut guard = lock.lock().unwrap();
+                while guard.is_none() && worker_running.load(Ordering::SeqCst) {
+                    guard = cvar.wait(guard).unwrap();
+                }
+                match guard.take() {
+                    Some(frame) => frame,
+                    None => break,
+                }
+            };
+            let latency = frame.captured.elapsed();
+
+            // When adapting, accumulate a luma histogram to re-run Otsu on.
+            if auto_treshold {
+                for pixel in frame.image.pixels() {
+                    accum[luma(pixel) as usize] += 1;
+                }
+            }
+
+            match mapping {
+                Mapping::Bar => {
+                    let mut global = 0;
+                    for pixel in frame.image.pixels() {
+                        if luma(pixel) > treshold {
+                            global += 1;
+                        }
+                    }
+                    gpio.signal(global/pindiv).expect("must set gpios");
+                }
+                Mapping::Regions => {
+                    let levels = region_levels(&frame.image, columns, treshold, pindiv);
+                    gpio.signal_regions(&levels).expect("must set gpios");
+                }
+            }
+
+            processed += 1;
+
+            // Periodically recompute Otsu so the threshold tracks changing light.
+            if auto_treshold && processed % 30 == 0 {
+                treshold = otsu_threshold(&accum);
+                accum = [0u64; 256];
+                println!("auto-treshold -> {}", treshold);
+            }
+            // Periodically report throughput so users can tell whether the Pi
+            // is keeping up with the configured fps.
+            if processed % 30 == 0 {
+                println!("processed={} dropped={} latency={:?}",
+                    processed, worker_dropped.load(Ordering::SeqCst), latency);
+            }
+        }
+    });
 
     // Get a stream, calling the closure as callback for every frame
-    let image : Vec<u8> = [0u8;614400].to_vec();
-    let image_rw = std::sync::RwLock::new(image);
+    let cb_slot = slot.clone();
+    let cb_dropped = dropped.clone();
     let stream = streamh
         .start_stream(
             move |_frame, count| {
-                let treshold = 230;
-                let pindiv = 100;
-
-                let video_frame = _frame.to_bytes();
-                let mut global = 0;
-                let mut offset = 0;
-                for _ in 0..480 {
-                    for _ in 0..640 {
-                        let v = video_frame[offset];
-                        if v > treshold {
-                            global+=1;
-                        }
-                        offset += 2;
+                let captured = Instant::now();
+                let image = match decode_frame(pixel_format, _frame.to_bytes(), _frame.width(), _frame.height()) {
+                    Ok(image) => image,
+                    Err(err) => {
+                        eprintln!("could not decode frame: {}", err);
+                        return;
                     }
-                }
+                };
 
-                gpio.signal(global/pindiv as usize).expect("must set gpios");
+                // Deposit the newest frame, counting a drop if the worker had not
+                // yet picked up the previous one.
+                let (lock, cvar) = &*cb_slot;
+                let mut guard = lock.lock().unwrap();
+                if guard.is_some() {
+                    cb_dropped.fetch_add(1, Ordering::SeqCst);
+                }
+                *guard = Some(CapturedFrame { image, captured });
+                cvar.notify_one();
+                drop(guard);
 
-                println!("{}",global);
                 count.fetch_add(1, Ordering::SeqCst);
             },
             counter.clone(),
         ).expect("Could not start stream");
 
-    // Wait 10 seconds
-    std::thread::sleep(Duration::new(180, 0));
+    // Run for the configured duration.
+    std::thread::sleep(Duration::new(duration, 0));
 
     // Explicitly stop the stream
     // The stream would also be stopped
     // when going out of scope (dropped)
     stream.stop();
+    drop(stream);
+    // Signal shutdown and wake the worker so it can observe it and exit.
+    // The flag is stored under the slot's mutex so the worker cannot check it
+    // and then miss the wake-up before it starts waiting.
+    {
+        let _guard = slot.0.lock().unwrap();
+        running.store(false, Ordering::SeqCst);
+    }
+    slot.1.notify_one();
+    let _ = worker.join();
     println!("Counter: {}", counter.load(Ordering::SeqCst));
 
     return Ok(());
@@ -188,17 +683,77 @@ fn main() -> Result<()> {
             SubCommand::with_name(CMD_LEDSTEST)
             .about("Test leds"))
         .subcommand(
-            SubCommand::with_name(CMD_RUN)
+            device_args(SubCommand::with_name(CMD_RUN))
             .about("Execute the application")
-            .arg(Arg::with_name(ARG_DEVICEID).required(true).takes_value(true).index(1)))
+            .arg(Arg::with_name(ARG_EXPOSURE).long(ARG_EXPOSURE).takes_value(true)
+                .help("Set the absolute exposure time"))
+            .arg(Arg::with_name(ARG_GAIN).long(ARG_GAIN).takes_value(true)
+                .help("Set the sensor gain"))
+            .arg(Arg::with_name(ARG_FOCUS_ABS).long(ARG_FOCUS_ABS).takes_value(true)
+                .help("Set the absolute focus"))
+            .arg(Arg::with_name(ARG_FOCUS_REL).long(ARG_FOCUS_REL).takes_value(true)
+                .help("Set the relative focus"))
+            .arg(Arg::with_name(ARG_WHITE_BALANCE).long(ARG_WHITE_BALANCE).takes_value(true)
+                .help("Set the white balance temperature"))
+            .arg(Arg::with_name(ARG_SCANNING_MODE).long(ARG_SCANNING_MODE).takes_value(true)
+                .help("Set the scanning mode (0 interlaced, 1 progressive)"))
+            .arg(Arg::with_name(ARG_AE_PRIORITY).long(ARG_AE_PRIORITY).takes_value(true)
+                .help("Set the auto-exposure priority"))
+            .arg(Arg::with_name(ARG_DUMP_CONTROLS).long(ARG_DUMP_CONTROLS).takes_value(false)
+                .help("Print the current value of every control and exit"))
+            .arg(Arg::with_name(ARG_MAPPING).long(ARG_MAPPING).takes_value(true)
+                .help("How brightness drives the LEDs: bar or regions"))
+            .arg(Arg::with_name(ARG_THRESHOLD).long(ARG_THRESHOLD).takes_value(true)
+                .help("Luma value above which a pixel counts as lit"))
+            .arg(Arg::with_name(ARG_AUTO_THRESHOLD).long(ARG_AUTO_THRESHOLD).takes_value(false)
+                .help("Recompute the threshold periodically with Otsu's method"))
+            .arg(Arg::with_name(ARG_WIDTH).long(ARG_WIDTH).takes_value(true)
+                .help("Requested capture width (default 640)"))
+            .arg(Arg::with_name(ARG_HEIGHT).long(ARG_HEIGHT).takes_value(true)
+                .help("Requested capture height (default 480)"))
+            .arg(Arg::with_name(ARG_FPS).long(ARG_FPS).takes_value(true)
+                .help("Requested capture frame rate (default 10)"))
+            .arg(Arg::with_name(ARG_FORMAT).long(ARG_FORMAT).takes_value(true)
+                .help("Requested pixel format: yuyv or mjpeg (default yuyv)"))
+            .arg(Arg::with_name(ARG_DURATION).long(ARG_DURATION).takes_value(true)
+                .help("How many seconds to run before stopping (default 180)")))
+        .subcommand(
+            device_args(SubCommand::with_name(CMD_CALIBRATE))
+            .about("Measure the scene and persist a threshold/scaling to disk"))
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .get_matches();
 
     if let Some(_) = matches.subcommand_matches(CMD_DEVICES) {
         cmd_device_list()
     } else if let Some(matches) = matches.subcommand_matches(CMD_RUN) {
-        let device_id = matches.value_of(ARG_DEVICEID).unwrap();
-        Ok(cmd_run(device_id.to_string()))
+        let query = device_query(matches)?;
+        let controls = CameraControls {
+            exposure_abs: matches.value_of(ARG_EXPOSURE).map(|s| s.parse()).transpose()?,
+            gain: matches.value_of(ARG_GAIN).map(|s| s.parse()).transpose()?,
+            focus_abs: matches.value_of(ARG_FOCUS_ABS).map(|s| s.parse()).transpose()?,
+            focus_rel: matches.value_of(ARG_FOCUS_REL).map(|s| s.parse()).transpose()?,
+            white_balance: matches.value_of(ARG_WHITE_BALANCE).map(|s| s.parse()).transpose()?,
+            scanning_mode: matches.value_of(ARG_SCANNING_MODE).map(|s| s.parse()).transpose()?,
+            ae_priority: matches.value_of(ARG_AE_PRIORITY).map(|s| s.parse()).transpose()?,
+        };
+        let dump = matches.is_present(ARG_DUMP_CONTROLS);
+        let mapping = match matches.value_of(ARG_MAPPING) {
+            Some(value) => value.parse()?,
+            None => Mapping::Bar,
+        };
+        let treshold = matches.value_of(ARG_THRESHOLD).map(|s| s.parse()).transpose()?;
+        let auto_treshold = matches.is_present(ARG_AUTO_THRESHOLD);
+        let requested = uvc::StreamFormat {
+            width: matches.value_of(ARG_WIDTH).map(|s| s.parse()).transpose()?.unwrap_or(640),
+            height: matches.value_of(ARG_HEIGHT).map(|s| s.parse()).transpose()?.unwrap_or(480),
+            fps: matches.value_of(ARG_FPS).map(|s| s.parse()).transpose()?.unwrap_or(10),
+            format: matches.value_of(ARG_FORMAT).map(parse_frame_format).transpose()?.unwrap_or(uvc::FrameFormat::YUYV),
+        };
+        let duration = matches.value_of(ARG_DURATION).map(|s| s.parse()).transpose()?.unwrap_or(DEFAULT_DURATION);
+        cmd_run(query, requested, controls, dump, mapping, treshold, auto_treshold, duration);
+        Ok(())
+    } else if let Some(matches) = matches.subcommand_matches(CMD_CALIBRATE) {
+        cmd_calibrate(device_query(matches)?)
     } else if let Some(matches) = matches.subcommand_matches(CMD_LEDSTEST) {
         cmd_leds_test();
         Ok(())
@@ -206,3 +761,49 @@ fn main() -> Result<()> {
         unreachable!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn otsu_threshold_splits_a_bimodal_histogram() {
+        let mut histogram = [0u64; 256];
+        histogram[50] = 1000; // dark cluster
+        histogram[200] = 1000; // bright cluster
+        let treshold = otsu_threshold(&histogram);
+        // Between-class variance is flat across every split between the two
+        // clusters, so `otsu_threshold` (first-max) legitimately lands on the
+        // low cluster's own bin rather than strictly between the clusters.
+        assert!(treshold >= 50 && treshold < 200, "treshold {} not between the clusters", treshold);
+    }
+
+    #[test]
+    fn otsu_threshold_on_empty_histogram_falls_back_to_default() {
+        assert_eq!(otsu_threshold(&[0u64; 256]), 230);
+    }
+
+    #[test]
+    fn over_treshold_counts_only_brighter_bins() {
+        let mut histogram = [0u64; 256];
+        histogram[100] = 5;
+        histogram[200] = 7;
+        assert_eq!(over_treshold(&histogram, 150), 7);
+        assert_eq!(over_treshold(&histogram, 50), 12);
+    }
+
+    #[test]
+    fn region_levels_scales_by_pindiv_not_raw_area() {
+        // A 4x1 image, left half bright, right half dark.
+        let mut image = RgbImage::new(4, 1);
+        image.put_pixel(0, 0, image::Rgb([255, 255, 255]));
+        image.put_pixel(1, 0, image::Rgb([255, 255, 255]));
+        image.put_pixel(2, 0, image::Rgb([0, 0, 0]));
+        image.put_pixel(3, 0, image::Rgb([0, 0, 0]));
+
+        // Each column covers 2 pixels; with pindiv 4 the bright column is 0.5,
+        // where scaling by the column's area would give 1.0.
+        let levels = region_levels(&image, 2, 128, 4);
+        assert_eq!(levels, vec![0.5, 0.0]);
+    }
+}
@@ -6,13 +6,16 @@ use clap::{Arg, App as ClapApp, SubCommand, AppSettings};
 use std::io::prelude::*;
 use nannou::prelude::*;
 use once_cell::sync::OnceCell;
-use nannou::image::{RgbImage, DynamicImage, Rgb};
+use image::{RgbImage, Rgb};
+
+mod decode;
+use decode::{decode_frame, luma};
 
 const CMD_DEVICES : &str = "devices";
 const CMD_RUN : &str = "run";
 const ARG_DEVICEID : &str = "deviceid";
 
-static VIDEO_FRAME: OnceCell<std::sync::RwLock<Vec<u8>>> = OnceCell::new();
+static VIDEO_FRAME: OnceCell<std::sync::RwLock<RgbImage>> = OnceCell::new();
 
 fn cmd_device_list() -> Result<()> {
     let ctx = uvc::Context::new().expect("Could not get context");
@@ -33,8 +36,14 @@ fn cmd_device_list() -> Result<()> {
 
 fn cmd_run(device_id : String) {
 
+    let format = uvc::StreamFormat {
+        width: 640,
+        height: 480,
+        fps: 30,
+        format: uvc::FrameFormat::YUYV,
+    };
     std::thread::spawn(move || {
-        capture_video(device_id).expect("failed to capture video");
+        capture_video(device_id, format).expect("failed to capture video");
     });
 
     nannou::app(model).run();
@@ -60,24 +69,25 @@ fn view(app: &App, model: &Model, frame: Frame) {
     if let Some(video_frame) = VIDEO_FRAME.get() {
         if let Ok(video_frame) = video_frame.try_read() {
             let mut global : u64 = 0;
-            let mut image = RgbImage::new(640, 480);
-            let mut offset = 0;
-            for y in 0..480 {
-                for x in 0..640 {
-                    let treshold = 200;
-                    let v = video_frame[offset];
-                    if v > treshold {
-                        global+=1;
-                        image.put_pixel(x, y, Rgb([255,0,0]));
-                    } else {
-                        image.put_pixel(x, y, Rgb([v,v,v]));
-                    }
-                    offset += 2;
+            let mut image = RgbImage::new(video_frame.width(), video_frame.height());
+            for (x, y, pixel) in video_frame.enumerate_pixels() {
+                let treshold = 200;
+                if luma(pixel) > treshold {
+                    global += 1;
+                    image.put_pixel(x, y, Rgb([255, 0, 0]));
+                } else {
+                    image.put_pixel(x, y, *pixel);
                 }
             }
 
-            let image = DynamicImage::ImageRgb8(image);
-            let texture = wgpu::Texture::from_image(app, &image);
+            // `wgpu::Texture::from_image` takes nannou's re-exported `image`
+            // crate, not the `image` dependency `decode` uses to build frames;
+            // rebuild the buffer through nannou's own type rather than relying
+            // on the two crates resolving to the same version.
+            let (width, height) = image.dimensions();
+            let nannou_image = nannou::image::RgbImage::from_raw(width, height, image.into_raw())
+                .expect("pixel buffer matches image dimensions");
+            let texture = wgpu::Texture::from_image(app, &nannou::image::DynamicImage::ImageRgb8(nannou_image));
             draw.texture(&texture);
 
             draw.text(&format!("{}",global));
@@ -87,7 +97,7 @@ fn view(app: &App, model: &Model, frame: Frame) {
     draw.to_frame(app, &frame).unwrap();
 }
 
-fn capture_video(device_id : String) -> Result<()> {
+fn capture_video(device_id : String, format : uvc::StreamFormat) -> Result<()> {
     // Get a libuvc context
     let ctx = uvc::Context::new()?;
     let bus_address :Vec<_> = device_id.split(":").collect();
@@ -113,15 +123,8 @@ fn capture_video(device_id : String) -> Result<()> {
         }
     };
 
-    // Most webcams support this format
-    let format = uvc::StreamFormat {
-        width: 640,
-        height: 480,
-        fps: 30,
-        format: uvc::FrameFormat::YUYV,
-    };
-
     devh.set_ae_mode(uvc::AutoExposureMode::Manual).expect("cannot disable auto exposure");
+    let pixel_format = format.format;
     // Get the necessary stream information
     let mut streamh = devh
         .get_stream_handle_with_format(format)
@@ -133,17 +136,20 @@ fn capture_video(device_id : String) -> Result<()> {
     let counter = Arc::new(AtomicUsize::new(0));
 
     // Get a stream, calling the closure as callback for every frame
-    let image : Vec<u8> = [0u8;614400].to_vec();
-    let image_rw = std::sync::RwLock::new(image);
+    let image_rw = std::sync::RwLock::new(RgbImage::new(format.width, format.height));
     VIDEO_FRAME.set(image_rw).expect("cannot set image_rw");
     let stream = streamh
         .start_stream(
-            |_frame, count| {
-                if let Some(video_frame) = VIDEO_FRAME.get() {
-                    if let Ok(mut instance) = video_frame.write() {
-                        instance.clear();
-                        instance.append(&mut Vec::from(_frame.to_bytes()));
+            move |_frame, count| {
+                match decode_frame(pixel_format, _frame.to_bytes(), _frame.width(), _frame.height()) {
+                    Ok(image) => {
+                        if let Some(video_frame) = VIDEO_FRAME.get() {
+                            if let Ok(mut instance) = video_frame.write() {
+                                *instance = image;
+                            }
+                        }
                     }
+                    Err(err) => eprintln!("could not decode frame: {}", err),
                 }
                 count.fetch_add(1, Ordering::SeqCst);
             },
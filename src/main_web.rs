@@ -1,9 +1,129 @@
 #[macro_use]
 extern crate serde_json;
 
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use actix_web::web::Bytes;
 use actix_web::{get, web, App, HttpServer, HttpResponse, Responder};
-use handlebars::Handlebars;
 use anyhow::Result;
+use futures::stream;
+use handlebars::Handlebars;
+
+mod decode;
+use decode::{decode_frame, luma};
+
+/// Number of vertical slices `capture_into` reduces each frame into for the
+/// `/events` per-region feed, matching the pin count the LED binaries drive.
+const REGION_COLUMNS: usize = 8;
+
+/// Latest processed frame shared between the capture thread and the HTTP
+/// handlers. Generalizes the single-frame `OnceCell` the capture binaries use
+/// into something several readers can observe concurrently.
+struct Shared {
+    /// Most recent frame, JPEG-encoded for direct delivery to the browser.
+    jpeg: RwLock<Vec<u8>>,
+    /// Most recent aggregate brightness level, normalized to `0.0..=1.0`.
+    level: RwLock<f32>,
+    /// Most recent per-region brightness, one `0.0..=1.0` value per
+    /// `REGION_COLUMNS` vertical slice, left to right.
+    regions: RwLock<Vec<f32>>,
+}
+
+impl Shared {
+    fn new() -> Self {
+        Shared {
+            jpeg: RwLock::new(Vec::new()),
+            level: RwLock::new(0.0),
+            regions: RwLock::new(vec![0.0; REGION_COLUMNS]),
+        }
+    }
+}
+
+/// Over-threshold luma fraction of each of `REGION_COLUMNS` equal vertical
+/// slices, for display in the `/events` feed.
+fn region_levels(image: &image::RgbImage, treshold: u8) -> Vec<f32> {
+    let width = image.width();
+    let mut counts = vec![0u32; REGION_COLUMNS];
+    for (x, _y, pixel) in image.enumerate_pixels() {
+        if luma(pixel) > treshold {
+            let column = (x as usize * REGION_COLUMNS / width.max(1) as usize).min(REGION_COLUMNS - 1);
+            counts[column] += 1;
+        }
+    }
+    let per_column = (image.width() * image.height()) as f32 / REGION_COLUMNS as f32;
+    counts.iter().map(|&c| if per_column > 0.0 { c as f32 / per_column } else { 0.0 }).collect()
+}
+
+/// Capture frames from the first available device and publish the latest
+/// JPEG, aggregate brightness level, and per-region levels into `shared`,
+/// forever.
+fn capture_into(shared: Arc<Shared>) -> Result<()> {
+    let ctx = uvc::Context::new()?;
+    let dev = match ctx.devices()?.next() {
+        Some(dev) => dev,
+        None => {
+            println!("No capture device found");
+            return Ok(());
+        }
+    };
+    let devh = dev.open()?;
+
+    let format = uvc::StreamFormat {
+        width: 640,
+        height: 480,
+        fps: 10,
+        format: uvc::FrameFormat::YUYV,
+    };
+    let pixel_format = format.format;
+    devh.set_ae_mode(uvc::AutoExposureMode::Manual).ok();
+    let mut streamh = devh.get_stream_handle_with_format(format)?;
+
+    let treshold = 230;
+    let stream = streamh
+        .start_stream(
+            move |frame, shared| {
+                let image = match decode_frame(pixel_format, frame.to_bytes(), frame.width(), frame.height()) {
+                    Ok(image) => image,
+                    Err(err) => {
+                        eprintln!("could not decode frame: {}", err);
+                        return;
+                    }
+                };
+
+                let mut global = 0u64;
+                for pixel in image.pixels() {
+                    if luma(pixel) > treshold {
+                        global += 1;
+                    }
+                }
+                let total = (image.width() * image.height()).max(1) as f32;
+                let regions = region_levels(&image, treshold);
+
+                let mut jpeg = Vec::new();
+                if image::DynamicImage::ImageRgb8(image)
+                    .write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(80))
+                    .is_ok()
+                {
+                    if let Ok(mut latest) = shared.jpeg.write() {
+                        *latest = jpeg;
+                    }
+                }
+                if let Ok(mut level) = shared.level.write() {
+                    *level = global as f32 / total;
+                }
+                if let Ok(mut latest) = shared.regions.write() {
+                    *latest = regions;
+                }
+            },
+            shared,
+        )?;
+
+    // The server keeps running; hold the stream open for the life of the process.
+    std::thread::park();
+    stream.stop();
+    Ok(())
+}
 
 #[get("/{name}/index.html")]
 async fn index(web::Path(name): web::Path<String>) -> impl Responder {
@@ -14,10 +134,64 @@ async fn index(web::Path(name): web::Path<String>) -> impl Responder {
     HttpResponse::Ok().body(html)
 }
 
+/// Serve the latest frames as a browser-viewable `multipart/x-mixed-replace`
+/// MJPEG stream.
+#[get("/stream.mjpeg")]
+async fn stream_mjpeg(shared: web::Data<Arc<Shared>>) -> impl Responder {
+    let boundary = "maimaiframe";
+    let shared = shared.get_ref().clone();
+    let body = stream::unfold(shared, move |shared| async move {
+        actix_rt::time::delay_for(Duration::from_millis(100)).await;
+        let jpeg = shared.jpeg.read().map(|j| j.clone()).unwrap_or_default();
+        let mut chunk = Vec::new();
+        chunk.extend_from_slice(
+            format!("--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                boundary, jpeg.len()).as_bytes());
+        chunk.extend_from_slice(&jpeg);
+        chunk.extend_from_slice(b"\r\n");
+        Some((Ok::<_, actix_web::Error>(Bytes::from(chunk)), shared))
+    });
+    HttpResponse::Ok()
+        .content_type(format!("multipart/x-mixed-replace; boundary={}", boundary))
+        .streaming(body)
+}
+
+/// Push the current brightness level and per-region levels to the browser as
+/// Server-Sent Events.
+#[get("/events")]
+async fn events(shared: web::Data<Arc<Shared>>) -> impl Responder {
+    let shared = shared.get_ref().clone();
+    let body = stream::unfold(shared, move |shared| async move {
+        actix_rt::time::delay_for(Duration::from_millis(500)).await;
+        let level = shared.level.read().map(|l| *l).unwrap_or(0.0);
+        let regions = shared.regions.read().map(|r| r.clone()).unwrap_or_default();
+        let data = format!("data: {}\n\n", json!({"level": level, "regions": regions}));
+        Some((Ok::<_, actix_web::Error>(Bytes::from(data)), shared))
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .streaming(body)
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| App::new().service(index))
-        .bind("0.0.0.0:8080")?
-        .run()
-        .await
+    let shared = Arc::new(Shared::new());
+
+    let capture = shared.clone();
+    std::thread::spawn(move || {
+        if let Err(err) = capture_into(capture) {
+            eprintln!("capture stopped: {}", err);
+        }
+    });
+
+    HttpServer::new(move || {
+        App::new()
+            .data(shared.clone())
+            .service(index)
+            .service(stream_mjpeg)
+            .service(events)
+    })
+    .bind("0.0.0.0:8080")?
+    .run()
+    .await
 }